@@ -1,12 +1,4 @@
-extern crate futures;
-extern crate tokio_core;
-extern crate raii_change_tracker;
-
-use futures::{Future, IntoFuture, Stream};
-use tokio_core::reactor::{Core, Timeout};
-use std::time::Duration;
-use std::rc::Rc;
-use std::cell::RefCell;
+use futures::StreamExt;
 
 use raii_change_tracker::DataTracker;
 
@@ -15,40 +7,23 @@ struct StoreType {
     val: i32,
 }
 
-fn main() {
-
-    let data_store_rc = Rc::new(RefCell::new(DataTracker::new(StoreType { val: 123 })));
-    let rx = data_store_rc.borrow_mut().add_listener();
-    let rx_printer = rx.for_each(|(old_value, new_value)| {
-                                     println!("got change message: old: {:?}, new: {:?}",
-                                              old_value,
-                                              new_value);
-                                     futures::future::err(()) // return error to abort stream
-                                 });
+#[tokio::main]
+async fn main() {
 
-    let mut core = Core::new().unwrap();
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener();
 
-    let dsclone = data_store_rc.clone();
-    let cause_change = Timeout::new(Duration::from_millis(0), &core.handle())
-        .into_future()
-        .flatten()
-        .and_then(move |_t| {
-            {
-                let mut data_store = dsclone.borrow_mut();
-                let mut scoped_store = data_store.as_tracked_mut();
-                println!("initial value {:?}", (*scoped_store).val);
-                (*scoped_store).val += 1;
-            }
-            Ok(())
-        })
-        .map_err(|e| {
-                     println!("error during timeout handling: {:?}", e);
-                 });
+    {
+        let mut scoped_store = data_store.as_tracked_mut();
+        println!("initial value {:?}", scoped_store.val);
+        scoped_store.val += 1;
+    }
 
-    core.handle().spawn(cause_change);
-    match core.run(rx_printer) {
-        Ok(_) => unreachable!(),
-        Err(()) => println!("reactor core loop done."),
+    if let Some((old_value, new_value)) = rx.next().await {
+        println!("got change message: old: {:?}, new: {:?}",
+                  old_value,
+                  new_value);
     }
-    println!("final value {:?}", data_store_rc.borrow().as_ref().val);
+
+    println!("final value {:?}", data_store.as_ref().val);
 }
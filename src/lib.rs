@@ -44,15 +44,7 @@
 //! ## example
 //!
 //! ```
-//! extern crate futures;
-//! extern crate tokio_core;
-//! extern crate raii_change_tracker;
-//!
-//! use futures::{Future, IntoFuture, Stream};
-//! use tokio_core::reactor::{Core, Timeout};
-//! use std::time::Duration;
-//! use std::rc::Rc;
-//! use std::cell::RefCell;
+//! use futures::StreamExt;
 //!
 //! use raii_change_tracker::DataTracker;
 //!
@@ -61,58 +53,38 @@
 //!     val: i32,
 //! }
 //!
-//! fn main() {
-//!
+//! #[tokio::main]
+//! async fn main() {
 //!     // Create our DataTracker instance.
-//!     let data_store = DataTracker::new(StoreType { val: 123 });
-//!     // Wrap it so we can clone it.
-//!     let data_store_rc = Rc::new(RefCell::new(data_store));
+//!     let mut data_store = DataTracker::new(StoreType { val: 123 });
 //!     // Create a listener futures::Stream to receive all changes.
-//!     let rx = data_store_rc.borrow_mut().add_listener();
-//!     // For each change notification, do this.
-//!     let rx_printer = rx.for_each(|(old_value, new_value)| {
-//!                                      // In this example, we just verify things work.
-//!                                      assert!(old_value.val == 123);
-//!                                      assert!(new_value.val == 124);
-//!                                      futures::future::err(()) // return error to abort stream
-//!                                  });
-//!
-//!     // Create an instance of a tokio reactor.
-//!     let mut core = Core::new().unwrap();
-//!
-//!     // Clone our DataTracker instance.
-//!     let dsclone = data_store_rc.clone();
-//!     // Create a timeout and then, when it fires, update the data store.
-//!     let cause_change = Timeout::new(Duration::from_millis(0), &core.handle())
-//!         .into_future()
-//!         .flatten()
-//!         .and_then(move |_| {
-//!             {
-//!                 let mut data_store = dsclone.borrow_mut();
-//!                 let mut scoped_store = data_store.as_tracked_mut();
-//!                 assert!((*scoped_store).val == 123);
-//!                 (*scoped_store).val += 1;
-//!             }
-//!             Ok(())
-//!         })
-//!         .map_err(|_| ());
+//!     let mut rx = data_store.add_listener();
 //!
-//!     // Run our futures in the tokio event loop.
-//!     core.handle().spawn(cause_change);
-//!     match core.run(rx_printer) {
-//!         Ok(_) => unreachable!(),
-//!         Err(()) => (),
+//!     {
+//!         let mut scoped_store = data_store.as_tracked_mut();
+//!         assert!((*scoped_store).val == 123);
+//!         (*scoped_store).val += 1;
 //!     }
 //!
+//!     // Await the change notification.
+//!     let (old_value, new_value) = rx.next().await.unwrap();
+//!     assert!(old_value.val == 123);
+//!     assert!(new_value.val == 124);
+//!
 //!     // Check that the value was incremented.
-//!     assert!(data_store_rc.borrow().as_ref().val == 124);
+//!     assert!(data_store.as_ref().val == 124);
 //! }
 //! ```
 
-extern crate futures;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
-use futures::sync::mpsc;
-use futures::{Future, Sink};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, FutureExt};
+use futures::stream::Stream;
 
 /// Allow viewing and modifying data owned by `DataTracker`.
 ///
@@ -132,10 +104,7 @@ impl<'a, T> Modifier<'a, T>
 {
     fn new(inner: &'a mut Inner<T>) -> Modifier<'a, T> {
         let orig_copy: T = inner.value.clone();
-        Modifier {
-            orig_copy: orig_copy,
-            inner_ref: inner,
-        }
+        Modifier { orig_copy, inner_ref: inner }
     }
 }
 
@@ -169,31 +138,129 @@ impl<'a, T> Drop for Modifier<'a, T>
     }
 }
 
+// A single subscriber's channel together with any `(old, new)` tuples that
+// could not be sent immediately because the channel was full. `pending` is
+// shared with the `ChangeStream` handed out to the subscriber so it can be
+// drained as soon as the subscriber polls, rather than waiting on the next
+// change to be pushed through `notify_listeners`. Shared via `Arc<Mutex<_>>`
+// rather than `Rc<RefCell<_>>` so that `ChangeStream` stays `Send` for
+// `T: Send` and can be `tokio::spawn`ed onto a multi-threaded runtime.
+struct ListenerSlot<T> {
+    tx: mpsc::Sender<(T, T)>,
+    pending: Arc<Mutex<VecDeque<(T, T)>>>,
+    // Caps how many overflowed tuples we hold for a listener that never
+    // drains; once full, the oldest queued tuple is dropped to make room.
+    pending_cap: usize,
+}
+
+/// A `Stream` of `(old, new)` tuples delivered to a single subscriber.
+///
+/// Returned by [`DataTracker::add_listener`](struct.DataTracker.html#method.add_listener)
+/// and friends. Drop it to unsubscribe.
+pub struct ChangeStream<T> {
+    rx: mpsc::Receiver<(T, T)>,
+    pending: Arc<Mutex<VecDeque<(T, T)>>>,
+}
+
+impl<T> Stream for ChangeStream<T>
+    where T: Unpin
+{
+    type Item = (T, T);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<(T, T)>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.rx).poll_next(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+            // The channel is either empty or (if `None`) has been closed by
+            // the `DataTracker` being dropped; either way, anything left in
+            // the overflow queue is still owed to this subscriber.
+            other => {
+                match this.pending.lock().unwrap().pop_front() {
+                    Some(item) => Poll::Ready(Some(item)),
+                    None => other,
+                }
+            }
+        }
+    }
+}
+
+// A predicate registered via `DataTracker::when()`, together with the
+// oneshot it should resolve once the predicate holds.
+struct Waiter<T> {
+    pred: Box<dyn Fn(&T) -> bool>,
+    tx: oneshot::Sender<T>,
+}
+
 // holds the actual store in `value`
 struct Inner<T>
     where T: Clone + PartialEq
 {
     value: T,
-    tx_map: Vec<mpsc::Sender<(T, T)>>,
+    tx_map: Vec<ListenerSlot<T>>,
+    waiters: Vec<Waiter<T>>,
 }
 
 impl<T> Inner<T>
     where T: Clone + PartialEq
 {
     fn notify_listeners(&mut self, orig_value: T, new_value: T) {
-        let mut to_return = Vec::new();
-        let orig_map = std::mem::replace(&mut self.tx_map, Vec::new());
-        for on_changed_tx in orig_map.into_iter() {
-            match on_changed_tx
-                      .send((orig_value.clone(), new_value.clone()))
-                      .wait() { // TODO remove .wait() here
-                Ok(t) => to_return.push(t),
-                Err(_) => continue,
+        let slots = std::mem::take(&mut self.tx_map);
+        for mut slot in slots.into_iter() {
+            {
+                let mut pending = slot.pending.lock().unwrap();
+                if pending.len() >= slot.pending_cap {
+                    // The listener isn't draining; drop the oldest tuple
+                    // rather than let the backlog grow without bound.
+                    pending.pop_front();
+                }
+                pending.push_back((orig_value.clone(), new_value.clone()));
+            }
+            if Inner::flush(&mut slot) {
+                self.tx_map.push(slot);
+            }
+            // else: the receiver was dropped, so prune this listener.
+        }
+        self.check_waiters(&new_value);
+    }
+
+    // Resolve and remove any `when()`/`when_eq()` futures whose predicate now
+    // holds against `new_value`. Futures whose receiver has already been
+    // dropped are pruned without evaluating their predicate.
+    fn check_waiters(&mut self, new_value: &T) {
+        let waiters = std::mem::take(&mut self.waiters);
+        for waiter in waiters.into_iter() {
+            if waiter.tx.is_canceled() {
+                continue;
+            }
+            if (waiter.pred)(new_value) {
+                let _ = waiter.tx.send(new_value.clone());
+            } else {
+                self.waiters.push(waiter);
             }
         }
-        for el in to_return.into_iter() {
-            self.tx_map.push(el);
+    }
+
+    // Send as much of a listener's pending backlog as possible without
+    // blocking. A merely-full channel leaves the remaining tuples queued in
+    // `slot.pending` for the subscriber's `ChangeStream` to pick up as soon
+    // as it polls, or for the next call here; a dropped receiver is reported
+    // by returning `false` so the caller can prune the listener.
+    fn flush(slot: &mut ListenerSlot<T>) -> bool {
+        let mut pending = slot.pending.lock().unwrap();
+        while let Some(item) = pending.pop_front() {
+            match slot.tx.try_send(item) {
+                Ok(()) => {}
+                Err(e) => {
+                    if e.is_disconnected() {
+                        return false;
+                    }
+                    // Channel full: put the tuple back and try again later.
+                    pending.push_front(e.into_inner());
+                    break;
+                }
+            }
         }
+        true
     }
 }
 
@@ -218,27 +285,132 @@ impl<T> DataTracker<T>
     pub fn new(value: T) -> DataTracker<T> {
         DataTracker {
             inner: Inner {
-                value: value,
+                value,
                 tx_map: Vec::new(),
+                waiters: Vec::new(),
             },
         }
     }
 
+    // Build the sender/receiver halves of a new subscription without
+    // registering it; callers decide when to push the resulting slot onto
+    // `tx_map`, which lets `add_listener_with_current` seed `pending` first.
+    fn new_channel(&self, buffer: usize) -> (ListenerSlot<T>, ChangeStream<T>) {
+        let (tx, rx) = mpsc::channel(buffer);
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        let slot = ListenerSlot {
+            tx,
+            pending: pending.clone(),
+            pending_cap: buffer.max(1),
+        };
+        (slot, ChangeStream { rx, pending })
+    }
+
     /// Add a callback that will be called just after a data change is detected.
     ///
-    /// Returns a Receiver which will receive messages whenever a change occurs.
+    /// Returns a `ChangeStream` which will receive messages whenever a change occurs.
+    ///
+    /// To remove a listener, drop the `ChangeStream`.
+    ///
+    /// The returned channel has a buffer of 1. Use
+    /// [`add_listener_with_capacity`](#method.add_listener_with_capacity) to
+    /// choose a larger buffer for listeners that may fall behind.
+    pub fn add_listener(&mut self) -> ChangeStream<T> {
+        self.add_listener_with_capacity(1)
+    }
+
+    /// Like [`add_listener`](#method.add_listener), but lets the caller pick
+    /// the channel's buffer size.
+    ///
+    /// A larger buffer lets a slow consumer fall further behind the producer
+    /// before changes are lost. Changes that do not fit in the channel are
+    /// held in an overflow backlog of at most `buffer` tuples (at least one);
+    /// once that is full, the oldest undelivered tuple is discarded for each
+    /// new change.
+    pub fn add_listener_with_capacity(&mut self, buffer: usize) -> ChangeStream<T> {
+        let (slot, stream) = self.new_channel(buffer);
+        self.inner.tx_map.push(slot);
+        stream
+    }
+
+    /// Like [`add_listener`](#method.add_listener), but enqueues the current
+    /// value as an initial `(current, current)` tuple before returning.
     ///
-    /// To remove a listener, drop the Receiver.
-    pub fn add_listener(&mut self) -> mpsc::Receiver<(T, T)> {
-        let (tx, rx) = mpsc::channel(1);
-        self.inner.tx_map.push(tx);
-        rx
+    /// This lets a newly-joined listener (for example a reactive UI view)
+    /// render the present state immediately and then treat every later
+    /// message as a diff, rather than seeing nothing until the next change.
+    pub fn add_listener_with_current(&mut self) -> ChangeStream<T> {
+        let (mut slot, stream) = self.new_channel(1);
+        let current = self.inner.value.clone();
+        slot.pending.lock().unwrap().push_back((current.clone(), current));
+        Inner::flush(&mut slot);
+        self.inner.tx_map.push(slot);
+        stream
     }
 
     /// Return a `Modifier` which can be used to modify the owned data.
-    pub fn as_tracked_mut(&mut self) -> Modifier<T> {
+    pub fn as_tracked_mut(&mut self) -> Modifier<'_, T> {
         Modifier::new(&mut self.inner)
     }
+
+    /// Modify the owned data within a closure, notifying listeners if it changed.
+    ///
+    /// This is a single-expression alternative to `as_tracked_mut()` for
+    /// callers who do not want to manage the lifetime of a `Modifier` guard.
+    /// A copy of the value is taken before `f` runs and compared against the
+    /// result afterwards; listeners are only notified if the two differ.
+    pub fn modify<F>(&mut self, f: F)
+        where F: FnOnce(&mut T)
+    {
+        let orig_copy: T = self.inner.value.clone();
+        f(&mut self.inner.value);
+        if orig_copy != self.inner.value {
+            let new_value = self.inner.value.clone();
+            self.inner.notify_listeners(orig_copy, new_value);
+        }
+    }
+
+    /// Return a future which resolves with a clone of the value once `pred`
+    /// returns `true` for it.
+    ///
+    /// If `pred` already holds for the current value, the returned future
+    /// resolves immediately. Otherwise `pred` is re-checked against every
+    /// subsequent change until it passes. Dropping the returned future
+    /// cancels the registration. If the `DataTracker` itself is dropped
+    /// before `pred` ever holds, the future is simply never woken again
+    /// rather than panicking or resolving with a bogus value — callers
+    /// waiting on it indefinitely should race it against a timeout.
+    ///
+    /// The returned future is `Box<dyn Future>` rather than `Box<dyn Future +
+    /// Send>`, so it can be awaited directly but not `tokio::spawn`ed onto a
+    /// multi-threaded runtime; spawn it onto a `LocalSet` (or a
+    /// current-thread runtime) if that's needed.
+    pub fn when<P>(&mut self, pred: P) -> Pin<Box<dyn Future<Output = T>>>
+        where P: Fn(&T) -> bool + 'static,
+              T: 'static
+    {
+        if pred(&self.inner.value) {
+            return future::ready(self.inner.value.clone()).boxed_local();
+        }
+        let (tx, rx) = oneshot::channel();
+        self.inner.waiters.push(Waiter { pred: Box::new(pred), tx });
+        async move {
+            match rx.await {
+                Ok(value) => value,
+                // The `DataTracker` was dropped before `pred` held, so it
+                // never will; stay pending forever instead of panicking.
+                Err(_) => future::pending().await,
+            }
+        }.boxed_local()
+    }
+
+    /// Convenience wrapper around [`when`](#method.when) for waiting until
+    /// the value becomes equal to `target`.
+    pub fn when_eq(&mut self, target: T) -> Pin<Box<dyn Future<Output = T>>>
+        where T: 'static
+    {
+        self.when(move |value| *value == target)
+    }
 }
 
 impl<T> AsRef<T> for DataTracker<T>
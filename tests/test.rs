@@ -1,53 +1,238 @@
-extern crate futures;
-extern crate tokio_core;
-extern crate raii_change_tracker;
-
-use futures::{Future, IntoFuture, Stream};
-use tokio_core::reactor::{Core, Timeout};
 use std::time::Duration;
-use std::rc::Rc;
-use std::cell::RefCell;
+
+use futures::StreamExt;
 
 use raii_change_tracker::DataTracker;
 
+#[tokio::test]
+async fn test_increment() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener();
+
+    {
+        let mut scoped_store = data_store.as_tracked_mut();
+        assert!(scoped_store.val == 123);
+        scoped_store.val += 1;
+    }
+
+    let (old_value, new_value) = rx.next().await.unwrap();
+    assert!(old_value.val == 123);
+    assert!(new_value.val == 124);
+
+    assert!(data_store.as_ref().val == 124);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_change_stream_is_send() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener();
+
+    // `ChangeStream` must be `Send` so it can be polled from a task spawned
+    // onto a multi-threaded runtime, not just awaited inline.
+    let handle = tokio::spawn(async move { rx.next().await });
+
+    {
+        let mut scoped_store = data_store.as_tracked_mut();
+        scoped_store.val += 1;
+    }
+
+    let (old_value, new_value) = handle.await.unwrap().unwrap();
+    assert!(old_value.val == 123);
+    assert!(new_value.val == 124);
+}
+
+#[tokio::test]
+async fn test_add_listener_with_capacity() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    // With a buffer of 2, both changes below are queued in the channel before
+    // either is consumed, and are delivered in order.
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener_with_capacity(2);
+
+    {
+        let mut scoped_store = data_store.as_tracked_mut();
+        scoped_store.val += 1;
+    }
+    {
+        let mut scoped_store = data_store.as_tracked_mut();
+        scoped_store.val += 1;
+    }
+
+    let first = rx.next().await.unwrap();
+    let second = rx.next().await.unwrap();
+    assert!(first.1.val == 124);
+    assert!(second.1.val == 125);
+}
+
 #[test]
-fn test_increment() {
+fn test_full_channel_does_not_block() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    // With capacity 1 and nothing consuming `_rx`, the old `.send(..).wait()`
+    // implementation would block here forever after the first change.
+    // `modify()` must return promptly regardless of how far behind the
+    // listener has fallen.
+    let mut data_store = DataTracker::new(StoreType { val: 0 });
+    let _rx = data_store.add_listener_with_capacity(1);
+
+    for _ in 0..5 {
+        data_store.modify(|store| { store.val += 1; });
+    }
+
+    assert!(data_store.as_ref().val == 5);
+}
+
+#[tokio::test]
+async fn test_overflow_delivered_without_further_changes() {
 
     #[derive(Clone,PartialEq,Debug)]
     struct StoreType {
         val: i32,
     }
 
-    let data_store_rc = Rc::new(RefCell::new(DataTracker::new(StoreType { val: 123 })));
-    let rx = data_store_rc.borrow_mut().add_listener();
-    let rx_printer = rx.for_each(|(old_value, new_value)| {
-                                     assert!(old_value.val == 123);
-                                     assert!(new_value.val == 124);
-                                     futures::future::err(()) // return error to abort stream
-                                 });
+    // A buffer-of-1 channel holds two tuples (its buffer plus the sender's
+    // own slot), so the third change overflows into the pending backlog. All
+    // three changes must still be observable even though no further change
+    // ever happens to retrigger a flush from `notify_listeners`.
+    let mut data_store = DataTracker::new(StoreType { val: 0 });
+    let mut rx = data_store.add_listener_with_capacity(1);
 
-    let mut core = Core::new().unwrap();
+    data_store.modify(|store| { store.val = 1; });
+    data_store.modify(|store| { store.val = 2; });
+    data_store.modify(|store| { store.val = 3; });
 
-    let dsclone = data_store_rc.clone();
-    let cause_change = Timeout::new(Duration::from_millis(0), &core.handle())
-        .into_future()
-        .flatten()
-        .and_then(move |_| {
-            {
-                let mut data_store = dsclone.borrow_mut();
-                let mut scoped_store = data_store.as_tracked_mut();
-                assert!((*scoped_store).val == 123);
-                (*scoped_store).val += 1;
-            }
-            Ok(())
-        })
-        .map_err(|_| ());
+    let first = rx.next().await.unwrap();
+    let second = rx.next().await.unwrap();
+    let third = rx.next().await.unwrap();
+    assert!(first.1.val == 1);
+    assert!(second.1.val == 2);
+    assert!(third.1.val == 3);
+}
+
+#[tokio::test]
+async fn test_pending_backlog_is_bounded() {
 
-    core.handle().spawn(cause_change);
-    match core.run(rx_printer) {
-        Ok(_) => unreachable!(),
-        Err(()) => (),
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
     }
 
-    assert!(data_store_rc.borrow().as_ref().val == 124);
+    // A listener that never drains must not accumulate an unbounded backlog.
+    let mut data_store = DataTracker::new(StoreType { val: 0 });
+    let mut rx = data_store.add_listener_with_capacity(1);
+
+    for i in 1..=20 {
+        data_store.modify(|store| { store.val = i; });
+    }
+
+    let mut last = None;
+    let mut received = 0;
+    while let Ok(Some(item)) = tokio::time::timeout(Duration::from_millis(50), rx.next()).await {
+        last = Some(item);
+        received += 1;
+    }
+    // At most the two tuples the channel holds plus one overflowed tuple
+    // (the backlog cap equals the buffer size) are delivered, not all 20.
+    assert!(received <= 3);
+    // The latest value must still show up even though the backlog was capped.
+    assert!(last.unwrap().1.val == 20);
+}
+
+#[tokio::test]
+async fn test_add_listener_with_current() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener_with_current();
+
+    data_store.modify(|store| { store.val += 1; });
+
+    // The first message is the initial snapshot, not a diff.
+    let first = rx.next().await.unwrap();
+    assert!(first.0.val == 123);
+    assert!(first.1.val == 123);
+    // The second message is the real change.
+    let second = rx.next().await.unwrap();
+    assert!(second.0.val == 123);
+    assert!(second.1.val == 124);
+}
+
+#[tokio::test]
+async fn test_when_eq() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let when_connected = data_store.when_eq(StoreType { val: 124 });
+
+    data_store.modify(|store| { store.val += 1; });
+
+    let value = when_connected.await;
+    assert!(value.val == 124);
+}
+
+#[tokio::test]
+async fn test_when_stays_pending_after_tracker_dropped() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let pending = data_store.when_eq(StoreType { val: 124 });
+    // The predicate never held before the tracker went away, so it never
+    // will; the future must stay pending rather than panic.
+    drop(data_store);
+
+    let result = tokio::time::timeout(Duration::from_millis(50), pending).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_modify() {
+
+    #[derive(Clone,PartialEq,Debug)]
+    struct StoreType {
+        val: i32,
+    }
+
+    let mut data_store = DataTracker::new(StoreType { val: 123 });
+    let mut rx = data_store.add_listener();
+
+    data_store.modify(|store| { store.val += 1; });
+
+    let (old_value, new_value) = rx.next().await.unwrap();
+    assert!(old_value.val == 123);
+    assert!(new_value.val == 124);
+
+    assert!(data_store.as_ref().val == 124);
 }